@@ -0,0 +1,29 @@
+#!/usr/bin/dash
+
+set -o errexit
+
+# Prints the installed components, targets and extra tools, separated by 255.
+if command -v rustup > /dev/null; then
+	rustup component list --installed
+	printf '\377'
+	rustup target list --installed
+else
+	# Toolchains from the standalone installer record their components in
+	# the sysroot, and have a lib directory for each target.
+	sysroot=$(rustc --print sysroot)
+	if [ -f "$sysroot/lib/rustlib/components" ]; then
+		cat "$sysroot/lib/rustlib/components"
+	fi
+	printf '\377'
+	for lib in "$sysroot"/lib/rustlib/*/lib; do
+		if [ -d "$lib" ]; then
+			basename "$(dirname "$lib")"
+		fi
+	done
+fi
+printf '\377'
+for tool in cargo rustdoc rustfmt clippy-driver miri; do
+	if command -v "$tool" > /dev/null; then
+		echo "$tool"
+	fi
+done
@@ -2,13 +2,78 @@
 
 set -o errexit
 
-TERM=xterm rustc - -o ./out "$@"
+start=$(date +%s%N)
+
+# The first argument is the length of the source in bytes. Only that much is
+# fed to rustc, and the rest of stdin is left over for the program to read.
+size=$1
+shift
+
+# Optionally followed by:
+#  --time-limit=NAME:MS, asking for the program to be told in $NAME how many
+#    of the MS milliseconds it's allowed are left.
+#  --resource-usage, asking for GNU time's report on the program to be printed
+#    after its output, following another 255.
+#  --umask=MODE, for the program to run with.
+time_limit=
+resource_usage=
+program_umask=
+while true; do
+	case "$1" in
+	--time-limit=*)
+		time_limit=${1#--time-limit=}
+		shift
+		;;
+	--resource-usage)
+		resource_usage=1
+		shift
+		;;
+	--umask=*)
+		program_umask=${1#--umask=}
+		shift
+		;;
+	*)
+		break
+		;;
+	esac
+done
+
+run() {
+	if [ -n "$program_umask" ]; then
+		umask "$program_umask"
+	fi
+	if [ -n "$time_limit" ]; then
+		elapsed=$(( ($(date +%s%N) - start) / 1000000 ))
+		set -- env "${time_limit%%:*}=$(( ${time_limit#*:} - elapsed ))" "$@"
+	fi
+	if [ -z "$resource_usage" ]; then
+		exec "$@"
+	fi
+
+	# The report is left empty if the image doesn't have GNU time.
+	status=0
+	if [ -x /usr/bin/time ]; then
+		LANG=C /usr/bin/time -v -o ./usage "$@" || status=$?
+	else
+		"$@" || status=$?
+	fi
+	printf '\377'
+	if [ -f ./usage ]; then
+		cat ./usage
+	fi
+	exit "$status"
+}
+
+head -c "$size" | TERM=xterm rustc - -o ./out "$@"
+# Only reached once rustc succeeded, which is how web.py tells rustc's exit
+# status apart from the program's.
 printf '\377' # 255 in octal
 if [ "${*#*--test}" != "$*" ] && [ "${*#*--color=always}" != "$*" ]; then
         # For /evaluate.json, we have {test: true, color: true}. Let's make the
         # output coloured too.  This would be better in web.py, but we don't
         # have an easy way to allot parameters for ./out.
-	TERM=xterm exec ./out --color=always
+	export TERM=xterm
+	run ./out --color=always
 else
-	exec ./out
+	run ./out
 fi
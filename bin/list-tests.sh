@@ -0,0 +1,7 @@
+#!/usr/bin/dash
+
+set -o errexit
+
+TERM=xterm rustc - --test -o ./out "$@"
+printf '\377' # 255 in octal
+exec ./out --list
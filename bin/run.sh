@@ -0,0 +1,14 @@
+#!/usr/bin/dash
+
+set -o errexit
+
+# The first argument is the size of an already compiled program in bytes. It's
+# read from the front of stdin, and the rest is left over for the program. The
+# second is the umask to run it with.
+head -c "$1" > ./out
+chmod +x ./out
+umask "$2"
+# Marks where the program's output starts, so that a program printing what
+# looks like one of playpen's own errors can't be mistaken for one.
+printf '\377' # 255 in octal
+exec ./out
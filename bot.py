@@ -50,7 +50,7 @@ def evaluate(code, nickname):
         code = irc_template % {"version": version.decode(), "input": code}
 
     out, _ = playpen.execute("stable", "/usr/local/bin/evaluate.sh",
-                             ("-C","opt-level=2",), code)
+                             (str(len(code.encode())), "-C","opt-level=2",), code)
 
     if len(out) > 5000:
         return "more than 5000 bytes of output; bailing out"
@@ -19,6 +19,7 @@ pacstrap -c -d root-nightly.new \
     pacman \
     procps-ng \
     shadow \
+    time \
     util-linux \
     gcc
 
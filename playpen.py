@@ -1,13 +1,53 @@
 #!/usr/bin/env python3
 
+import os
+import posixpath
 import subprocess
 
+# How many seconds a command may run before playpen kills it.
+TIMEOUT = 5
+
+TIMEOUT_MESSAGE = b"playpen: timeout triggered!\n"
+
+# An optional in-container script to run every command through, e.g. to set up
+# auditing or another layer of sandboxing. It gets the original command and
+# arguments as its own and is expected to exec them.
+ENTRYPOINT_WRAPPER = os.environ.get("PLAYPEN_ENTRYPOINT_WRAPPER") or None
+
+class WrapperMissing(OSError):
+    """The entrypoint wrapper isn't in a channel's root. That's a problem with
+    the configuration or the image, not with playpen."""
+
+def check_wrapper(wrapper):
+    # Fail at startup rather than on every request to a channel.
+    if not posixpath.isabs(wrapper) or posixpath.normpath(wrapper) != wrapper:
+        raise ValueError("entrypoint wrapper must be a normalized absolute path: " + wrapper)
+    # The roots sit next to this file, but web.py only changes into that
+    # directory after importing it.
+    here = os.path.dirname(os.path.abspath(__file__))
+    for version in ("stable", "beta", "nightly"):
+        root = os.path.join(here, "root-" + version)
+        if os.path.isdir(root) and not os.path.isfile(root + wrapper):
+            raise ValueError("entrypoint wrapper {} is missing from root-{}".format(wrapper, version))
+
+if ENTRYPOINT_WRAPPER is not None:
+    check_wrapper(ENTRYPOINT_WRAPPER)
+
+def wrap(version, command, arguments):
+    # Checked again here since init.sh can replace a root while running.
+    wrapper = ENTRYPOINT_WRAPPER
+    if not os.path.isfile("root-" + version + wrapper):
+        raise WrapperMissing("entrypoint wrapper {} is missing from root-{}".format(wrapper, version))
+    return wrapper, (command,) + arguments
+
 def execute(version, command, arguments, data=None):
+    if ENTRYPOINT_WRAPPER is not None:
+        command, arguments = wrap(version, command, arguments)
     with subprocess.Popen(("playpen",
                            "root-" + version,
                            "--mount-proc",
                            "--user=rust",
-                           "--timeout=5",
+                           "--timeout=%d" % TIMEOUT,
                            "--syscalls-file=whitelist",
                            "--devices=/dev/urandom:r,/dev/null:w",
                            "--memory-limit=128",
@@ -18,6 +58,17 @@ def execute(version, command, arguments, data=None):
                            stderr=subprocess.STDOUT) as p:
         if data is None:
             out = p.communicate()[0]
+        elif isinstance(data, bytes):
+            out = p.communicate(data)[0]
         else:
             out = p.communicate(data.encode())[0]
         return (out, p.returncode)
+
+def timed_out(out):
+    return out.endswith(TIMEOUT_MESSAGE)
+
+def failed_to_start(out):
+    """Whether playpen gave up before running the command, e.g. because the
+    sandbox couldn't be set up. playpen reports its own errors prefixed with
+    its name, and they come before anything the command could print."""
+    return out.startswith(b"playpen: ") and not out.startswith(TIMEOUT_MESSAGE)
@@ -0,0 +1,128 @@
+#!/usr/bin/env python3
+
+import io
+import json
+import unittest
+from unittest import mock
+
+from bottle import request
+
+import playpen
+import web
+
+def bind_json(body):
+    data = json.dumps(body).encode()
+    request.bind({"REQUEST_METHOD": "POST",
+                  "CONTENT_TYPE": "application/json",
+                  "CONTENT_LENGTH": str(len(data)),
+                  "wsgi.input": io.BytesIO(data)})
+
+class TestCaching(unittest.TestCase):
+    def setUp(self):
+        web.cache = web.Cache(maxsize=256)
+        web.breaker = web.CircuitBreaker(web.PLAYPEN_FAILURE_THRESHOLD, web.PLAYPEN_COOLDOWN)
+
+    def test_playpen_failure_is_not_cacheable(self):
+        self.assertFalse(web.cacheable(b"playpen: failed to create cgroup\n"))
+
+    def test_compile_error_is_cacheable(self):
+        self.assertTrue(web.cacheable(b"error[E0425]: cannot find value `x`\n"))
+
+    def test_program_output_is_not_a_playpen_failure(self):
+        self.assertFalse(playpen.failed_to_start(b"\xffplaypen: hello\n"))
+        self.assertFalse(playpen.failed_to_start(playpen.TIMEOUT_MESSAGE))
+
+    def test_transient_failure_is_retried(self):
+        results = [(b"playpen: failed to create cgroup\n", 1), (b"\xffhello\n", 0)]
+        with mock.patch("playpen.execute", side_effect=results) as execute:
+            self.assertEqual(web.execute("stable", "/bin/true", (), "")[1], 1)
+            self.assertEqual(web.execute("stable", "/bin/true", (), "")[1], 0)
+            self.assertEqual(web.execute("stable", "/bin/true", (), "")[1], 0)
+        self.assertEqual(execute.call_count, 2)
+
+    def test_link_outputs_are_not_stored(self):
+        with mock.patch("playpen.execute", return_value=(b"\xffout 1\nx", 0)) as execute:
+            web.execute("stable", "/bin/true", (), "", store=False)
+            web.execute("stable", "/bin/true", (), "", store=False)
+        self.assertEqual(execute.call_count, 2)
+
+class TestCircuitBreaker(unittest.TestCase):
+    def test_opens_after_threshold(self):
+        breaker = web.CircuitBreaker(2, 60)
+        breaker.record(False)
+        self.assertTrue(breaker.allow())
+        breaker.record(False)
+        self.assertFalse(breaker.allow())
+
+    def test_allows_one_trial_after_cooldown(self):
+        breaker = web.CircuitBreaker(1, 0)
+        breaker.record(False)
+        self.assertTrue(breaker.allow())
+        self.assertFalse(breaker.allow())
+        breaker.record(True)
+        self.assertTrue(breaker.allow())
+        self.assertTrue(breaker.allow())
+
+class TestLinkErrors(unittest.TestCase):
+    def test_gnu_ld_undefined_symbol(self):
+        output = ("error: linking with `cc` failed: exit status: 1\n"
+                  "  = note: main.o: undefined reference to `foo_missing'\n")
+        self.assertEqual(web.link_error_summary(output),
+                         "the code compiled, but linking failed because nothing defines foo_missing")
+
+    def test_lld_undefined_symbol(self):
+        output = ("error: linking with `cc` failed: exit status: 1\n"
+                  "  = note: rust-lld: error: undefined symbol: foo_missing\n")
+        self.assertEqual(web.link_error_summary(output),
+                         "the code compiled, but linking failed because nothing defines foo_missing")
+
+    def test_compile_error(self):
+        self.assertIsNone(web.link_error_summary("error[E0425]: cannot find value `x`\n"))
+
+class TestListedTests(unittest.TestCase):
+    def test_three_tests_and_a_bench(self):
+        listing = "tests::a: test\ntests::b: test\nc: test\nd: bench\n\n3 tests, 1 benchmark\n"
+        self.assertEqual(web.LISTED_TEST.findall(listing),
+                         [("tests::a", "test"), ("tests::b", "test"), ("c", "test"), ("d", "bench")])
+
+class TestFormat(unittest.TestCase):
+    def setUp(self):
+        web.cache = web.Cache(maxsize=256)
+
+    def format(self, code, formatted):
+        bind_json({"code": code})
+        with mock.patch("playpen.execute", return_value=(b"\xff" + formatted.encode(), 0)):
+            return web.format()
+
+    def test_already_formatted(self):
+        self.assertEqual(self.format("fn main() { }\n", "fn main() { }\n"),
+                         {"result": "fn main() { }", "changed": False})
+
+    def test_reformatted(self):
+        self.assertTrue(self.format("fn main(){}\n", "fn main() { }\n")["changed"])
+
+class TestRustflags(unittest.TestCase):
+    def test_allowlisted(self):
+        self.assertEqual(web.parse_rustflags("-C target-cpu=native -Cdebug-assertions=on"),
+                         ["-C", "target-cpu=native", "-C", "debug-assertions=on"])
+
+    def test_rejected(self):
+        for flags in ("--emit=link", "-C codegen-units=16", "-Z threads=8"):
+            with self.assertRaises(ValueError):
+                web.parse_rustflags(flags)
+
+class TestArtifacts(unittest.TestCase):
+    def test_trim_emitted(self):
+        asm = '\t.file\t"main"\n\n_main:\n\tret\n\n\t.ident\t"rustc"\n'
+        self.assertEqual(web.trim_emitted(asm, "asm"), "_main:\n\tret\n")
+
+    def test_unpack(self):
+        self.assertEqual(web.unpack_artifacts(b"out.s 3\nabcout.ll 2\nde"),
+                         {"out.s": b"abc", "out.ll": b"de"})
+
+    def test_unpack_truncated(self):
+        with self.assertRaises(ValueError):
+            web.unpack_artifacts(b"out 10\nabc")
+
+if __name__ == "__main__":
+    unittest.main()
@@ -1,16 +1,145 @@
 #!/usr/bin/env python3
 
-import functools
+import base64
+import collections
+import concurrent.futures
+import contextlib
+import hashlib
+import io
+import json
 import os
+import posixpath
+import re
+import shlex
 import sys
+import threading
+import time
+import zipfile
 
-from bottle import get, request, response, route, run, static_file
+from bottle import HTTPError, abort, get, request, response, route, run, static_file
 from pygments import highlight
 from pygments.formatters import HtmlFormatter
-from pygments.lexers import GasLexer, LlvmLexer
+from pygments.lexers import get_lexer_by_name
+from pygments.util import ClassNotFound
 
 import playpen
 
+# Custom toolchains bundled into the images live under here. A requested
+# sysroot has to resolve to somewhere below it.
+SYSROOT_PREFIX = "/opt/sysroot/"
+
+# The codegen options an operator may set for every compile through
+# PLAYPEN_RUSTFLAGS. codegen-units isn't one of them, since the compiler limits
+# below would always override it.
+RUSTFLAGS_ALLOWLIST = ("target-cpu", "target-feature", "debug-assertions")
+
+def parse_rustflags(flags):
+    """Splits operator-supplied rustc flags, allowing only `-C option=value` for
+    the allowlisted options. Returns them normalized to separate arguments."""
+    args = []
+    tokens = iter(shlex.split(flags))
+    for token in tokens:
+        if token == "-C":
+            option = next(tokens, "")
+        elif token.startswith("-C"):
+            option = token[2:]
+        else:
+            raise ValueError("unsupported flag in PLAYPEN_RUSTFLAGS: " + token)
+        if option.split("=", 1)[0] not in RUSTFLAGS_ALLOWLIST:
+            raise ValueError("unsupported codegen option in PLAYPEN_RUSTFLAGS: " + option)
+        args.append("-C")
+        args.append(option)
+    return args
+
+# Passed to rustc before anything a request asks for. rustc keeps the last value
+# given for a codegen option, so per-request settings (like opt-level) override
+# these, and the limits below override both.
+BASE_RUSTFLAGS = parse_rustflags(os.environ.get("PLAYPEN_RUSTFLAGS", ""))
+
+# Caps on rustc's own parallelism, to keep the resources a compile of untrusted
+# code can take predictable within the playpen limits. -Z threads only exists
+# on nightly, so it's left to rustc's default unless set here.
+COMPILER_CODEGEN_UNITS = 1
+COMPILER_THREADS = None
+
+# The rustc flags behind each optional feature. A toolchain supports a feature
+# if an empty program compiles with its flags.
+CAPABILITIES = {
+    "test": ("--test",),
+    "asm": ("--emit=asm",),
+    "llvm-ir": ("--emit=llvm-ir",),
+    "intel-syntax": ("--emit=asm", "-C", "llvm-args=-x86-asm-syntax=intel"),
+    "format": ("-Z", "unstable-options", "--pretty"),
+    "threads": ("-Z", "threads=1"),
+    "verbose": ("-Z", "verbose"),
+    "verbose-internals": ("-Z", "verbose-internals"),
+    "edition-2015": ("--edition=2015",),
+    "edition-2018": ("--edition=2018",),
+    "edition-2021": ("--edition=2021",),
+    "edition-2024": ("--edition=2024",),
+}
+
+# The editions editions.json knows about, oldest first.
+EDITIONS = ("2015", "2018", "2021", "2024")
+
+# How long, in seconds, a probed capability is trusted for the same image.
+CAPABILITY_TTL = 60 * 60
+
+# The umask programs run with, from PLAYPEN_UMASK. By default the files a
+# program creates are only accessible to itself.
+PROGRAM_UMASK = os.environ.get("PLAYPEN_UMASK", "077")
+if not re.fullmatch("0?[0-7]{3}", PROGRAM_UMASK):
+    raise ValueError("invalid umask in PLAYPEN_UMASK: " + PROGRAM_UMASK)
+
+# The environment variable that tells a program how many milliseconds it has
+# left before playpen kills it, for requests that ask for it.
+TIME_LIMIT_ENV = "PLAYGROUND_TIME_LIMIT_MS"
+
+# After this many playpen failures in a row, requests that miss the cache are
+# turned away for PLAYPEN_COOLDOWN seconds instead of piling up behind a broken
+# sandbox. After the cooldown a single request tries playpen again, and the
+# rest keep being turned away until it has a result.
+PLAYPEN_FAILURE_THRESHOLD = 5
+PLAYPEN_COOLDOWN = 30
+
+# Whether runs that hit the playpen timeout are cached like any other. Caching
+# them means resubmitting an infinite loop costs nothing, which matters under
+# abuse. Not caching them means a raised timeout applies to snippets that ran
+# out of time before.
+CACHE_TIMEOUTS = True
+
+# The pygments lexer used to highlight each kind of output. Pygments versions
+# don't all agree on lexer names, so any of these can be overridden with
+# PLAYPEN_LEXERS, e.g. "asm=nasm,llvm-ir=llvm".
+LEXERS = {"asm": "gas", "llvm-ir": "llvm"}
+
+def parse_lexers(overrides):
+    lexers = dict(LEXERS)
+    for override in filter(None, overrides.split(",")):
+        output, _, name = override.partition("=")
+        if output not in lexers or not name:
+            raise ValueError("invalid lexer override in PLAYPEN_LEXERS: " + override)
+        lexers[output] = name
+    # Fail at startup, not on the first request that wants highlighting.
+    for output, name in lexers.items():
+        try:
+            get_lexer_by_name(name)
+        except ClassNotFound:
+            raise ValueError("pygments has no lexer named {} for {}".format(name, output))
+    return lexers
+
+LEXERS = parse_lexers(os.environ.get("PLAYPEN_LEXERS", ""))
+
+# Bumped whenever the layout of an exported cache changes.
+CACHE_EXPORT_FORMAT = 1
+
+# Whether /cache/export and /cache/import are served at all. Off unless the
+# operator sets PLAYPEN_CACHE_SHARING=1, since the cache holds everyone's code.
+CACHE_SHARING = os.environ.get("PLAYPEN_CACHE_SHARING") == "1"
+
+# The most bundle.zip will hand out, counting all of the artifacts together.
+BUNDLE_SIZE_LIMIT = 8 * 1024 * 1024
+
 @get("/")
 def serve_index():
     response = static_file("web.html", root="static")
@@ -31,10 +160,334 @@ def serve_index():
 def serve_static(path):
     return static_file(path, root="static")
 
-@functools.lru_cache(maxsize=256)
-def execute(version, command, arguments, code):
-    print("running:", version, command, arguments, file=sys.stderr, flush=True)
-    return playpen.execute(version, command, arguments, code)
+class Cache:
+    """A thread-safe LRU cache. Unlike functools.lru_cache, it leaves it up to
+    the caller whether a result gets stored at all. Each entry can carry a dict
+    of metadata about the result, which plays no part in lookups."""
+
+    def __init__(self, maxsize):
+        self.maxsize = maxsize
+        self.entries = collections.OrderedDict()
+        self.lock = threading.Lock()
+
+    def get(self, key):
+        with self.lock:
+            if key not in self.entries:
+                return None
+            self.entries.move_to_end(key)
+            return self.entries[key][0]
+
+    def put(self, key, value, metadata=None):
+        with self.lock:
+            self.entries[key] = (value, metadata or {})
+            self.entries.move_to_end(key)
+            while len(self.entries) > self.maxsize:
+                self.entries.popitem(last=False)
+
+    def metadata(self, key):
+        with self.lock:
+            entry = self.entries.get(key)
+            return None if entry is None else entry[1]
+
+    def items(self):
+        """A snapshot of the entries as (key, value, metadata) tuples."""
+        with self.lock:
+            return [(key, value, metadata)
+                    for key, (value, metadata) in self.entries.items()]
+
+cache = Cache(maxsize=256)
+
+class CircuitBreaker:
+    """Counts consecutive failures, and stops allowing attempts for a while
+    once there have been too many. After that only one attempt at a time is
+    allowed until one succeeds, which resets it."""
+
+    def __init__(self, threshold, cooldown):
+        self.threshold = threshold
+        self.cooldown = cooldown
+        self.failures = 0
+        self.open_until = 0
+        self.trial = False
+        self.lock = threading.Lock()
+
+    def allow(self):
+        """Whether to make an attempt. Every allowed attempt has to be
+        followed by a record."""
+        with self.lock:
+            if self.failures < self.threshold:
+                return True
+            if self.trial or time.monotonic() < self.open_until:
+                return False
+            self.trial = True
+            return True
+
+    def record(self, succeeded):
+        """Records how an attempt went, with None for one that says nothing
+        about whether the next would work."""
+        with self.lock:
+            self.trial = False
+            if succeeded is None:
+                return
+            if succeeded:
+                self.failures = 0
+                return
+            # Left at or above the threshold, so a failed retry after the
+            # cooldown opens it again straight away.
+            self.failures += 1
+            if self.failures >= self.threshold:
+                self.open_until = time.monotonic() + self.cooldown
+
+breaker = CircuitBreaker(PLAYPEN_FAILURE_THRESHOLD, PLAYPEN_COOLDOWN)
+
+def cache_key(version, command, arguments, code):
+    """The key execute caches a run under. It's made of everything that can
+    change what a run outputs: the channel, the script, every argument and the
+    whole input, stdin included."""
+    return (version, command, tuple(arguments), code)
+
+def cache_key_hash(key):
+    """A string identifying a cache key, for callers keeping their own cache in
+    front of this one. It's only stable for as long as the arguments web.py
+    passes for a given request stay the same, which isn't promised across
+    changes to the playground."""
+    return hashlib.sha256(json.dumps(key).encode()).hexdigest()
+
+def cacheable(out):
+    # When playpen itself fails the code never ran, so there's nothing to
+    # remember and a retry should get a fresh attempt.
+    if playpen.failed_to_start(out):
+        return False
+    return CACHE_TIMEOUTS or not playpen.timed_out(out)
+
+def run_playpen(version, command, arguments, data=None):
+    """Runs a command in playpen unless the breaker is open. Everything that
+    runs playpen goes through here, so that the breaker sees every attempt.
+    The command has to print something of its own before any output of the
+    code it runs, or that code could pass itself off as playpen failing."""
+    if not breaker.allow():
+        abort(503, "playpen is unavailable, try again shortly")
+    succeeded = None
+    try:
+        result = playpen.execute(version, command, arguments, data)
+        succeeded = not playpen.failed_to_start(result[0])
+        return result
+    except playpen.WrapperMissing:
+        # Only this channel is broken, so don't turn away the others.
+        raise
+    except OSError:
+        succeeded = False
+        raise
+    finally:
+        breaker.record(succeeded)
+
+def execute(version, command, arguments, code, store=True, timings=None):
+    """Runs a command through the cache. Callers pass store=False for results
+    too big to be worth keeping, like linked programs, which still reuse an
+    entry that's already there. If given a timings dict, it records how long
+    the cache lookup took, and how long the run took if there was one."""
+    if timings is None:
+        timings = {}
+    key = cache_key(version, command, arguments, code)
+    with timed(timings, "cache_lookup"):
+        result = cache.get(key)
+    if result is None:
+        print("running:", version, command, arguments, file=sys.stderr, flush=True)
+        with timed(timings, "execute"):
+            result = run_playpen(version, command, arguments, code)
+        if store and cacheable(result[0]):
+            cache.put(key, result, {"rustc": producing_rustc(version),
+                                    "image": producing_image(version)})
+    return result
+
+def repro_stamp(key):
+    """What's needed to reproduce a result later: the channel, the exact build
+    of rustc that produced it, the image it ran in, and the script and
+    arguments it was run with. If the stamp differs when a result does, the
+    difference explains it."""
+    version, command, arguments, _ = key
+    metadata = cache.metadata(key) or {}
+    return {"channel": version,
+            "rustc": metadata.get("rustc") or producing_rustc(version),
+            "image": metadata.get("image") or producing_image(version),
+            "script": command,
+            "arguments": list(arguments)}
+
+def producing_rustc(version):
+    # Only for telling later why a result changed, so it's not worth failing
+    # the request over.
+    try:
+        return toolchain_version(version)
+    except (HTTPError, OSError):
+        return None
+
+def producing_image(version):
+    # Hashed, since the inode and time behind it only mean anything when
+    # compared as a whole, and only on this host.
+    try:
+        return hashlib.sha256(repr(image_stamp(version)).encode()).hexdigest()
+    except OSError:
+        return None
+
+capability_cache = {}
+capability_lock = threading.Lock()
+
+def image_stamp(version):
+    # init.sh moves a whole new root into place when it updates a toolchain,
+    # so the root directory itself identifies the installed image.
+    st = os.stat("root-" + version)
+    return (st.st_ino, st.st_mtime_ns)
+
+def supports(version, capability):
+    key = (version, capability)
+    stamp = image_stamp(version)
+    now = time.monotonic()
+    with capability_lock:
+        cached = capability_cache.get(key)
+    if cached is not None and cached[0] == stamp and now - cached[1] < CAPABILITY_TTL:
+        return cached[2]
+
+    out, rc = run_playpen(version, "/usr/local/bin/compile.sh",
+                          CAPABILITIES[capability], "fn main() {}")
+    # Neither says anything about the toolchain, so they're not remembered.
+    if playpen.failed_to_start(out) or playpen.timed_out(out):
+        raise OSError(out.decode(errors="replace").strip())
+    result = rc == 0
+    with capability_lock:
+        capability_cache[key] = (stamp, now, result)
+    return result
+
+toolchain_versions = {}
+
+def toolchain_version(version):
+    """The `rustc -vV` output for a channel, which names the exact build."""
+    stamp = image_stamp(version)
+    cached = toolchain_versions.get(version)
+    if cached is not None and cached[0] == stamp:
+        return cached[1]
+
+    out, rc = run_playpen(version, "/bin/dash", ("-c", "--", "rustc -vV"))
+    if rc:
+        raise OSError(out.decode(errors="replace").strip())
+    result = out.decode()
+    toolchain_versions[version] = (stamp, result)
+    return result
+
+image_components_cache = {}
+
+def image_components(version):
+    """What a channel's image has installed, as lists of rustup components,
+    targets and extra tools. Images without rustup are inspected on a best
+    effort basis by components.sh."""
+    stamp = image_stamp(version)
+    now = time.monotonic()
+    cached = image_components_cache.get(version)
+    if cached is not None and cached[0] == stamp and now - cached[1] < CAPABILITY_TTL:
+        return cached[2]
+
+    out, rc = run_playpen(version, "/usr/local/bin/components.sh", ())
+    if rc:
+        raise OSError(out.decode(errors="replace").strip())
+    components, targets, tools = out.split(b"\xff")
+    result = {"components": components.decode().split(),
+              "targets": targets.decode().split(),
+              "tools": tools.decode().split()}
+    image_components_cache[version] = (stamp, now, result)
+    return result
+
+def export_cache():
+    """Serializes the cache so that another host can start out with it."""
+    entries = cache.items()
+    channels = {key[0] for key, _, _ in entries}
+    return json.dumps({
+        "format": CACHE_EXPORT_FORMAT,
+        "toolchains": {channel: toolchain_version(channel) for channel in channels},
+        "entries": [{"key": [version, command, list(arguments), code],
+                     "out": base64.b64encode(out).decode(),
+                     "rc": rc,
+                     "rustc": metadata.get("rustc")}
+                    for (version, command, arguments, code), (out, rc), metadata in entries],
+    }).encode()
+
+def import_cache(data):
+    """Loads entries written by export_cache, returning how many were kept.
+    Entries are skipped if they're malformed or the exporting host had a
+    different build of their channel, since the results might differ."""
+    exported = json.loads(data.decode())
+    if exported.get("format") != CACHE_EXPORT_FORMAT:
+        raise ValueError("unsupported cache export format")
+
+    compatible = set()
+    for channel, exported_version in exported["toolchains"].items():
+        if channel not in ("stable", "beta", "nightly"):
+            continue
+        try:
+            if toolchain_version(channel) == exported_version:
+                compatible.add(channel)
+        except (HTTPError, OSError):
+            pass
+
+    imported = 0
+    for entry in exported["entries"]:
+        try:
+            version, command, arguments, code = entry["key"]
+            if not all(isinstance(field, str) for field in (version, command, code)) \
+                    or not isinstance(arguments, list) \
+                    or not all(isinstance(argument, str) for argument in arguments) \
+                    or not isinstance(entry["rc"], int):
+                continue
+            result = (base64.b64decode(entry["out"], validate=True), entry["rc"])
+            key = (version, command, tuple(arguments), code)
+        except (KeyError, TypeError, ValueError):
+            continue
+        if version in compatible:
+            cache.put(key, result, {"rustc": entry.get("rustc")})
+            imported += 1
+    return imported
+
+def replay(token):
+    """Looks up a cached result by the cache_key hash an earlier response
+    carried, without needing the request again. Returns the key along with the
+    result, or None once the entry is no longer cached."""
+    for key, value, _ in cache.items():
+        if cache_key_hash(key) == token:
+            return key, value
+    return None
+
+def local_only(wrappee):
+    # The cache holds everyone's code, so only hand it to the host itself, and
+    # only if the operator turned sharing on. remote_addr would believe
+    # whatever X-Forwarded-For a client sends, so go by the socket's peer.
+    def wrapper(*args, **kwargs):
+        if not CACHE_SHARING:
+            abort(404)
+        if request.environ.get("REMOTE_ADDR") not in ("127.0.0.1", "::1"):
+            abort(403)
+        return wrappee(*args, **kwargs)
+    return wrapper
+
+@route("/cache/export", method="GET")
+@local_only
+def serve_cache_export():
+    response.set_header("Content-Type", "application/json")
+    return export_cache()
+
+@route("/cache/import", method="POST")
+@local_only
+def serve_cache_import():
+    try:
+        return {"imported": import_cache(request.body.read())}
+    except (AttributeError, KeyError, TypeError, ValueError) as e:
+        return {"error": "invalid cache export: {}".format(e)}
+
+@contextlib.contextmanager
+def timed(timings, phase):
+    # Timings are only reported, and kept apart from execute's arguments, so
+    # they can't leak into the cache key or the cached results.
+    start = time.monotonic()
+    try:
+        yield
+    finally:
+        timings[phase] = time.monotonic() - start
 
 def enable_post_cors(wrappee):
     def wrapper(*args, **kwargs):
@@ -57,33 +510,379 @@ def extractor(key, default, valid):
         return wrapper
     return decorator
 
+def reject_empty_code(wrappee):
+    # An empty program would only spin up playpen to fail with a confusing
+    # "main function not found", so turn it away unless asked not to.
+    def wrapper(*args, **kwargs):
+        if not request.json["code"].strip() and request.json.get("allow_empty") is not True:
+            return {"error": "no code to run"}
+        return wrappee(*args, **kwargs)
+    return wrapper
+
+class InContainerPaths:
+    """The valid values for an optional path inside the container: either None,
+    or an absolute path that doesn't escape the prefix once normalized."""
+
+    def __init__(self, prefix):
+        self.prefix = prefix
+
+    def __contains__(self, value):
+        if value is None:
+            return True
+        if not isinstance(value, str) or not posixpath.isabs(value):
+            return False
+        return posixpath.normpath(value).startswith(self.prefix)
+
+def compiler_limit_args(version):
+    args = ["-C", "codegen-units=%d" % COMPILER_CODEGEN_UNITS]
+    if version == "nightly" and COMPILER_THREADS is not None:
+        args.append("-Z")
+        args.append("threads=%d" % COMPILER_THREADS)
+    return args
+
+def rustc_args(version, optimize=None, debug=None):
+    """The arguments every compile starts with: the operator's flags, the
+    optimization level, debug info (by default only at level 0) and the
+    compiler limits. Every handler builds them here, so that requests for the
+    same build share a cache entry."""
+    args = list(BASE_RUSTFLAGS)
+    if optimize is not None:
+        args.append("-C")
+        args.append("opt-level=" + optimize)
+    if debug is None:
+        debug = optimize == "0"
+    if debug:
+        args.append("-g")
+    args.extend(compiler_limit_args(version))
+    return args
+
+def verbose_args(version):
+    """The flag for fully detailed diagnostics on this channel, or None if it
+    has neither. rustc renamed it from -Z verbose to -Z verbose-internals."""
+    for capability in ("verbose-internals", "verbose"):
+        if supports(version, capability):
+            return list(CAPABILITIES[capability])
+    return None
+
+def sysroot_args(sysroot):
+    if sysroot is None:
+        return []
+    return ["--sysroot", posixpath.normpath(sysroot)]
+
+RESOURCE_USAGE = {
+    "max_rss": re.compile(r"Maximum resident set size \(kbytes\): (\d+)"),
+    "user_time": re.compile(r"User time \(seconds\): ([\d.]+)"),
+    "system_time": re.compile(r"System time \(seconds\): ([\d.]+)"),
+}
+
+def parse_resource_usage(report):
+    """Picks the peak memory use (in bytes) and CPU times (in seconds) out of
+    GNU time's verbose report, leaving None for anything missing."""
+    usage = {}
+    for field, pattern in RESOURCE_USAGE.items():
+        match = pattern.search(report)
+        if match is None:
+            usage[field] = None
+        elif field == "max_rss":
+            usage[field] = int(match.group(1)) * 1024
+        else:
+            usage[field] = float(match.group(1))
+    return usage
+
+# Appended to the code for count_allocations. It wraps the system allocator to
+# count allocations, and reports the totals when the process exits, after a 255
+# so they can be told apart from the program's own output.
+ALLOCATION_COUNTER = r"""
+#[allow(dead_code)]
+mod __playground_allocation_counter {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counter;
+
+    unsafe impl GlobalAlloc for Counter {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTER: Counter = Counter;
+
+    extern "C" {
+        fn atexit(callback: extern "C" fn()) -> i32;
+        fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+    }
+
+    // Formats into a buffer on the stack, since allocating here would count.
+    extern "C" fn report() {
+        let mut buf = [0u8; 80];
+        let len = {
+            let mut cursor = &mut buf[..];
+            let _ = cursor.write_all(b"\xff");
+            let _ = write!(cursor, "allocations: {}, bytes: {}\n",
+                           ALLOCATIONS.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed));
+            80 - cursor.len()
+        };
+        unsafe { write(1, buf.as_ptr(), len); }
+    }
+
+    extern "C" fn register() {
+        unsafe { atexit(report); }
+    }
+
+    #[used]
+    #[link_section = ".init_array"]
+    static REGISTER: extern "C" fn() = register;
+}
+"""
+
+ALLOCATION_REPORT = re.compile(rb"\xffallocations: (\d+), bytes: (\d+)\n\Z")
+GLOBAL_ALLOCATOR = re.compile(r"#\s*\[\s*global_allocator\s*\]")
+
+LINK_FAILURE = re.compile("linking with `[^`]*` failed")
+# GNU ld's wording, else lld's, which newer toolchains link with.
+UNDEFINED_SYMBOL = re.compile(r"undefined reference to `([^']*)'|undefined symbol: (\S+)")
+
+def link_error_summary(rustc_output):
+    """A plainer description of the failure if it came from the linker, or None
+    for compile errors."""
+    if not LINK_FAILURE.search(rustc_output):
+        return None
+    symbols = sorted({ld or lld for ld, lld in UNDEFINED_SYMBOL.findall(rustc_output)})
+    if symbols:
+        return "the code compiled, but linking failed because nothing defines " + ", ".join(symbols)
+    return "the code compiled, but linking it into a program failed"
+
+def evaluation_result(key, out, rc, options):
+    """Turns the output of an evaluate.sh run into the result evaluate.json
+    returns, without the timings and request details. What the run measured is
+    read back from its cache key, and how to present it from options, so that
+    replay.json can render a cached run the same way the original request
+    did."""
+    _, _, arguments, code = key
+    report_usage = "--resource-usage" in arguments
+    # The source is the first size bytes of the input, and the size is always
+    # the first argument.
+    source = code.encode()[:int(arguments[0])].decode()
+    count_allocations = source.endswith(ALLOCATION_COUNTER)
+    timeout_message = options.get("timeout_message")
+
+    # playpen reports a timeout by printing its own message after the program's
+    # output. Callers going by timed_out can have that left out, or have it
+    # reworded with timeout_message. Either happens after caching, so that all
+    # of the variants share the same entry.
+    timed_out = playpen.timed_out(out)
+    if timed_out and options.get("timeout_marker") is False:
+        out = out[:-len(playpen.TIMEOUT_MESSAGE)]
+    elif timed_out and timeout_message is not None:
+        out = out[:-len(playpen.TIMEOUT_MESSAGE)] + timeout_message.encode() + b"\n"
+
+    # The report comes after the program's output, but only if the program
+    # finished before playpen's timeout.
+    if report_usage:
+        report = b""
+        if b"\xff" in out and not timed_out:
+            out, _, report = out.rpartition(b"\xff")
+        resource_usage = parse_resource_usage(report.decode(errors="replace"))
+
+    # The program prints this itself as it exits, so it's missing if the
+    # program never got that far.
+    allocations = None
+    if count_allocations:
+        match = ALLOCATION_REPORT.search(out)
+        if match:
+            out = out[:match.start()]
+            allocations = {"count": int(match.group(1)), "bytes": int(match.group(2))}
+
+    if options.get("separate_output") is True:
+        split = out.split(b"\xff", 1)
+
+        ret = {"rustc": split[0].decode()}
+        if len(split) == 2: # compilation succeeded
+            ret["program"] = split[1].decode(errors="replace")
+    else:
+        ret = {"result": out.replace(b"\xff", b"", 1).decode(errors="replace")}
+
+    ret["timed_out"] = timed_out
+    if report_usage:
+        ret["resource_usage"] = resource_usage
+    if count_allocations:
+        ret["allocations"] = allocations
+
+    # Say which step failed when there was no program to run, and explain
+    # linker failures, which look nothing like rustc's own errors.
+    split = out.split(b"\xff", 1)
+    if len(split) == 1:
+        summary = link_error_summary(split[0].decode(errors="replace"))
+        ret["phase"] = "compile" if summary is None else "link"
+        if summary is not None:
+            ret["summary"] = summary
+
+    # evaluate.sh stops with rustc's status as soon as rustc fails, and only
+    # prints the separator once it has succeeded. After that it execs the
+    # program, so the status is the program's. A timeout leaves playpen's own
+    # status instead, which says nothing about either.
+    if len(split) == 1:
+        ret["compile_exit"] = None if timed_out else rc
+        ret["run_exit"] = None
+    else:
+        ret["compile_exit"] = 0
+        ret["run_exit"] = None if timed_out else rc
+    return ret
+
 @route("/evaluate.json", method=["POST", "OPTIONS"])
 @enable_post_cors
+@reject_empty_code
+@extractor("verbose", False, (True, False))
+@extractor("sysroot", None, InContainerPaths(SYSROOT_PREFIX))
 @extractor("color", False, (True, False))
 @extractor("test", False, (True, False))
 @extractor("version", "stable", ("stable", "beta", "nightly"))
 @extractor("optimize", "2", ("0", "1", "2", "3"))
-def evaluate(optimize, version, test, color):
-    args = ["-C", "opt-level=" + optimize]
-    if optimize == "0":
-        args.append("-g")
+def evaluate(optimize, version, test, color, sysroot, verbose):
+    # Everything that can be rejected without playpen is checked before the
+    # verbose flag is probed for.
+    code = request.json["code"]
+    stdin = request.json.get("stdin", "")
+    if not isinstance(stdin, str):
+        return {"error": "invalid value for stdin"}
+    timeout_message = request.json.get("timeout_message")
+    if timeout_message is not None and not isinstance(timeout_message, str):
+        return {"error": "invalid value for timeout_message"}
+    count_allocations = request.json.get("count_allocations") is True
+    if count_allocations and GLOBAL_ALLOCATOR.search(code):
+        return {"error": "counting allocations needs the default allocator, "
+                         "but this code sets its own #[global_allocator]"}
+
+    args = rustc_args(version, optimize)
     if color:
         args.append("--color=always")
     if test:
         args.append("--test")
+    args.extend(sysroot_args(sysroot))
+    if verbose:
+        flag = verbose_args(version)
+        if flag is None:
+            return {"error": "verbose diagnostics aren't supported on " + version}
+        args.extend(flag)
 
-    out, _ = execute(version, "/usr/local/bin/evaluate.sh", tuple(args), request.json["code"])
+    # Appended rather than prepended so that line numbers in diagnostics still
+    # match what was submitted.
+    if count_allocations:
+        code += ALLOCATION_COUNTER
 
-    if request.json.get("separate_output") is True:
-        split = out.split(b"\xff", 1)
+    args.insert(0, "--umask=" + PROGRAM_UMASK)
+    report_usage = request.json.get("resource_usage") is True
+    if report_usage:
+        args.insert(0, "--resource-usage")
+    if request.json.get("time_limit_env") is True:
+        args.insert(0, "--time-limit=%s:%d" % (TIME_LIMIT_ENV, playpen.TIMEOUT * 1000))
+    # evaluate.sh splits the source off the front of its input by length, so
+    # both end up in the cache key without needing a separate argument.
+    args.insert(0, str(len(code.encode())))
+    key = cache_key(version, "/usr/local/bin/evaluate.sh", args, code + stdin)
+    # execute is left as None when the result came from the cache.
+    timings = {"cache_lookup": None, "execute": None}
+    out, rc = execute(*key, timings=timings)
+
+    ret = evaluation_result(key, out, rc, request.json)
+    ret.update(timings=timings, cached=timings["execute"] is None, cache_key=cache_key_hash(key),
+               timeout=playpen.TIMEOUT, stamp=repro_stamp(key))
+    return ret
+
+@route("/compare.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@reject_empty_code
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+def compare(version):
+    """Runs the program at several optimization levels. Output that differs
+    between them is a strong hint of undefined behaviour."""
+    levels = request.json.get("optimize", ["0", "3"])
+    if not isinstance(levels, list) or not levels or any(l not in ("0", "1", "2", "3") for l in levels):
+        return {"error": "invalid value for optimize"}
+    levels = sorted(set(levels))
 
+    code = request.json["code"]
+    stdin = request.json.get("stdin", "")
+    if not isinstance(stdin, str):
+        return {"error": "invalid value for stdin"}
+
+    def run(optimize):
+        args = [str(len(code.encode())), "--umask=" + PROGRAM_UMASK]
+        args.extend(rustc_args(version, optimize))
+        out, _ = execute(version, "/usr/local/bin/evaluate.sh", tuple(args), code + stdin)
+
+        split = out.split(b"\xff", 1)
         ret = {"rustc": split[0].decode()}
         if len(split) == 2: # compilation succeeded
             ret["program"] = split[1].decode(errors="replace")
-
         return ret
-    else:
-        return {"result": out.replace(b"\xff", b"", 1).decode(errors="replace")}
+
+    with concurrent.futures.ThreadPoolExecutor(len(levels)) as pool:
+        results = dict(zip(levels, pool.map(run, levels)))
+
+    programs = {result["program"] for result in results.values() if "program" in result}
+    return {"results": results, "diverged": len(programs) > 1}
+
+@route("/editions.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@reject_empty_code
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+def editions(version):
+    """Checks which editions the code compiles under, without running it. An
+    edition the toolchain doesn't have is reported as such, and doesn't stop
+    the others from being tried."""
+    requested = request.json.get("editions", list(EDITIONS))
+    if not isinstance(requested, list) or not requested or any(e not in EDITIONS for e in requested):
+        return {"error": "invalid value for editions"}
+    requested = sorted(set(requested))
+    code = request.json["code"]
+
+    def run(edition):
+        if not supports(version, "edition-" + edition):
+            return {"error": "edition {} isn't supported on {}".format(edition, version)}
+        # Only the metadata is emitted, which is enough for rustc to report
+        # every error without generating any code.
+        args = rustc_args(version) + ["--edition=" + edition, "--emit=metadata"]
+        out, _ = execute(version, "/usr/local/bin/compile.sh", tuple(args), code)
+        split = out.split(b"\xff", 1)
+        return {"compiled": len(split) == 2, "rustc": split[0].decode(errors="replace")}
+
+    with concurrent.futures.ThreadPoolExecutor(len(requested)) as pool:
+        results = dict(zip(requested, pool.map(run, requested)))
+
+    return {"results": results,
+            "compiled": [edition for edition, result in results.items() if result.get("compiled")]}
+
+LISTED_TEST = re.compile("^(.*): (test|bench)$", re.MULTILINE)
+
+@route("/tests.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@reject_empty_code
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+def list_tests(version):
+    """Builds the test harness and lists the tests and benchmarks it would run,
+    without running any of them."""
+    args = rustc_args(version)
+    out, rc = execute(version, "/usr/local/bin/list-tests.sh", tuple(args), request.json["code"])
+    split = out.split(b"\xff", 1)
+    if len(split) != 2 or rc:
+        return {"error": out.replace(b"\xff", b"", 1).decode(errors="replace")}
+
+    listed = LISTED_TEST.findall(split[1].decode(errors="replace"))
+    return {"tests": [name for name, kind in listed if kind == "test"],
+            "benches": [name for name, kind in listed if kind == "bench"]}
 
 @route("/format.json", method=["POST", "OPTIONS"])
 @enable_post_cors
@@ -94,25 +893,72 @@ def format(version):
     if rc:
         return {"error": split[0].decode()}
     else:
-        return {"result": split[1][:-1].decode()}
+        formatted = split[1][:-1].decode()
+        # So that editors can skip replacing the buffer when nothing changed.
+        # Compared before the pretty printer's final newline is trimmed off.
+        return {"result": formatted, "changed": split[1].decode() != request.json["code"]}
+
+# Lines in emitted code that say nothing about the program itself.
+BOILERPLATE = {
+    "asm": re.compile(r"\s*\.(file|ident)\b"),
+    "llvm-ir": re.compile(r"(; ModuleID|source_filename)\b"),
+}
+
+def trim_emitted(emitted, emit):
+    """Drops boilerplate lines and any blank lines around the rest."""
+    lines = [line for line in emitted.splitlines() if not BOILERPLATE[emit].match(line)]
+    while lines and not lines[0].strip():
+        del lines[0]
+    while lines and not lines[-1].strip():
+        del lines[-1]
+    return "".join(line + "\n" for line in lines)
 
 @route("/compile.json", method=["POST", "OPTIONS"])
 @enable_post_cors
+@reject_empty_code
+@extractor("verbose", False, (True, False))
+@extractor("sysroot", None, InContainerPaths(SYSROOT_PREFIX))
 @extractor("syntax", "att", ("att", "intel"))
 @extractor("color", False, (True, False))
 @extractor("version", "stable", ("stable", "beta", "nightly"))
 @extractor("optimize", "2", ("0", "1", "2", "3"))
 @extractor("emit", "asm", ("asm", "llvm-ir"))
-def compile(emit, optimize, version, color, syntax):
-    args = ["-C", "opt-level=" + optimize, "--emit=" + emit]
-    if optimize == "0":
-        args.append("-g")
+def compile(emit, optimize, version, color, syntax, sysroot, verbose):
+    args = rustc_args(version, optimize) + ["--emit=" + emit]
     if color:
         args.append("--color=always")
     if syntax:
         args.append("-C")
         args.append("llvm-args=-x86-asm-syntax=%s" % syntax)
-    out, _ = execute(version, "/usr/local/bin/compile.sh", tuple(args), request.json["code"])
+
+    # Instead of the final module, show the IR as it was after an LLVM pass, or
+    # after every pass for "all". LLVM prints these dumps along with rustc's
+    # own output. The new pass manager quietly ignores pass names it doesn't
+    # know, so no dump at all is taken to mean an unknown pass.
+    print_after = request.json.get("print_after")
+    if print_after is not None:
+        if emit != "llvm-ir" or not isinstance(print_after, str) \
+                or not re.fullmatch("[a-z][a-z0-9-]*", print_after):
+            return {"error": "invalid value for print_after"}
+        args.append("-C")
+        if print_after == "all":
+            args.append("llvm-args=-print-after-all")
+        else:
+            args.append("llvm-args=-print-after=" + print_after)
+
+    args.extend(sysroot_args(sysroot))
+    if verbose:
+        flag = verbose_args(version)
+        if flag is None:
+            return {"error": "verbose diagnostics aren't supported on " + version}
+        args.extend(flag)
+    key = cache_key(version, "/usr/local/bin/compile.sh", args, request.json["code"])
+    timings = {"cache_lookup": None, "execute": None, "highlight": None}
+    out, _ = execute(*key, timings=timings)
+    # Sent along with every response. timings is shared, so highlighting still
+    # shows up in it.
+    details = {"timings": timings, "cached": timings["execute"] is None,
+               "cache_key": cache_key_hash(key), "stamp": repro_stamp(key)}
     split = out.split(b"\xff", 1)
     if len(split) == 2:
         rustc_output = split[0].decode()
@@ -121,16 +967,302 @@ def compile(emit, optimize, version, color, syntax):
         rustc_output = split[0].decode()
         emitted = None
     if emitted is None:
-        return {"error": rustc_output}
+        return dict(details, error=rustc_output)
     else:
+        if print_after is not None:
+            if "*** IR Dump After" not in rustc_output:
+                return dict(details, error="LLVM ran no pass named " + print_after)
+            emitted = rustc_output
+        # Off by default, since the result is then no longer exactly what
+        # rustc emitted.
+        if request.json.get("trim") is True:
+            emitted = trim_emitted(emitted, emit)
         # You know, it might be good to include the rustc output in the same
         # way evaluate.json does rather than this different way. Ah well.
         # Compatibility and all that. Do we care? I really don't know!
         if request.json.get("highlight") is not True:
-            return {"result": split[1].decode()}
-        if emit == "asm":
-            return {"result": highlight(split[1].decode(), GasLexer(), HtmlFormatter(nowrap=True))}
-        return {"result": highlight(split[1].decode(), LlvmLexer(), HtmlFormatter(nowrap=True))}
+            return dict(details, result=emitted)
+        lexer = get_lexer_by_name(LEXERS[emit])
+        with timed(timings, "highlight"):
+            result = highlight(emitted, lexer, HtmlFormatter(nowrap=True))
+        return dict(details, result=result)
+
+@route("/replay.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+def serve_replay():
+    found = replay(request.json.get("token"))
+    if found is None:
+        return {"error": "no cached result for this token"}
+    key, (out, rc) = found
+    timeout_message = request.json.get("timeout_message")
+    if timeout_message is not None and not isinstance(timeout_message, str):
+        return {"error": "invalid value for timeout_message"}
+
+    # Runs of the program take the same presentation options as evaluate.json.
+    # Anything else is handed back split the same way, without interpretation.
+    if key[1] == "/usr/local/bin/evaluate.sh":
+        ret = evaluation_result(key, out, rc, request.json)
+    else:
+        split = out.split(b"\xff", 1)
+        ret = {"rustc": split[0].decode(errors="replace"), "exit_code": rc}
+        if len(split) == 2: # compilation succeeded
+            ret["result"] = split[1].decode(errors="replace")
+    ret.update(cache_key=request.json["token"], stamp=repro_stamp(key))
+    return ret
+
+@route("/capabilities.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+def capabilities(version):
+    return {name: supports(version, name) for name in CAPABILITIES}
+
+@route("/components.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+def components(version):
+    return image_components(version)
+
+# The most stdin fixtures fixtures.json runs a program against, and how many of
+# those runs go at once.
+MAX_FIXTURES = 16
+FIXTURE_CONCURRENCY = 4
+
+@route("/fixtures.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@reject_empty_code
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+@extractor("optimize", "2", ("0", "1", "2", "3"))
+def fixtures(optimize, version):
+    """Compiles the program once and runs it against each of the given stdin
+    inputs, every run in its own playpen with its own timeout."""
+    inputs = request.json.get("fixtures")
+    if not isinstance(inputs, list) or not 0 < len(inputs) <= MAX_FIXTURES \
+            or not all(isinstance(fixture, str) for fixture in inputs):
+        return {"error": "invalid value for fixtures"}
+
+    # Compiled once per request. The linked program is too big to keep in the
+    # cache, so grading the same program again builds it again.
+    args = rustc_args(version, optimize) + ["--emit=link"]
+    out, _ = execute(version, "/usr/local/bin/bundle.sh", tuple(args), request.json["code"],
+                     store=False)
+    split = out.split(b"\xff", 1)
+    if len(split) != 2:
+        return {"error": split[0].decode()}
+    try:
+        program = unpack_artifacts(split[1])["out"]
+    except (KeyError, ValueError):
+        return {"error": split[1].decode(errors="replace")}
+
+    def run(fixture):
+        out, rc = run_playpen(version, "/usr/local/bin/run.sh",
+                              (str(len(program)), PROGRAM_UMASK), program + fixture.encode())
+        timed_out = playpen.timed_out(out)
+        return {"output": out.replace(b"\xff", b"", 1).decode(errors="replace"),
+                "exit_code": None if timed_out else rc,
+                "timed_out": timed_out}
+
+    with concurrent.futures.ThreadPoolExecutor(min(len(inputs), FIXTURE_CONCURRENCY)) as pool:
+        results = list(pool.map(run, inputs))
+    return {"rustc": split[0].decode(), "results": results, "timeout": playpen.TIMEOUT}
+
+# The most requests batch.json takes at once, and how many of them run at a
+# time.
+MAX_BATCH = 64
+BATCH_CONCURRENCY = 4
+
+def call_with_body(callback, body):
+    # The endpoints assume a JSON object with the code as a string, and would
+    # fail the whole batch with anything else, so check that here.
+    if not isinstance(body, dict) or not isinstance(body.get("code"), str):
+        return {"error": "invalid request"}
+
+    # The endpoints read their options from the current request, so give each
+    # worker thread a request of its own carrying just its part of the batch.
+    data = json.dumps(body).encode()
+    request.bind({"REQUEST_METHOD": "POST",
+                  "CONTENT_TYPE": "application/json",
+                  "CONTENT_LENGTH": str(len(data)),
+                  "wsgi.input": io.BytesIO(data)})
+    response.bind()
+    try:
+        return callback()
+    except HTTPError as e:
+        return {"error": e.body}
+    except OSError as e:
+        return {"error": str(e)}
+
+@route("/batch.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+def batch():
+    """Runs a list of evaluate.json requests, returning their results in the
+    same order. Identical requests are only run once."""
+    requests = request.json.get("requests")
+    if not isinstance(requests, list) or not 0 < len(requests) <= MAX_BATCH:
+        return {"error": "invalid value for requests"}
+
+    unique = {}
+    for body in requests:
+        unique.setdefault(json.dumps(body, sort_keys=True), body)
+
+    with concurrent.futures.ThreadPoolExecutor(min(len(unique), BATCH_CONCURRENCY)) as pool:
+        results = dict(zip(unique, pool.map(lambda body: call_with_body(evaluate, body),
+                                            unique.values())))
+    return {"results": [results[json.dumps(body, sort_keys=True)] for body in requests]}
+
+# What bundle.zip calls each kind of artifact inside the archive.
+BUNDLE_NAMES = {"asm": "main.s", "llvm-ir": "main.ll", "link": "main"}
+
+def unpack_artifacts(data):
+    """Splits the output of bundle.sh into a dict from file name to contents."""
+    artifacts = {}
+    while data:
+        header, data = data.split(b"\n", 1)
+        name, size = header.decode().rsplit(" ", 1)
+        size = int(size)
+        if len(data) < size:
+            raise ValueError("artifact {} is truncated".format(name))
+        artifacts[name], data = data[:size], data[size:]
+    return artifacts
+
+@route("/bundle.zip", method=["POST", "OPTIONS"])
+@enable_post_cors
+@reject_empty_code
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+@extractor("optimize", "2", ("0", "1", "2", "3"))
+def bundle(optimize, version):
+    emit = request.json.get("emit", ["asm", "llvm-ir", "link"])
+    if not isinstance(emit, list) or not emit or any(e not in BUNDLE_NAMES for e in emit):
+        return {"error": "invalid value for emit"}
+    # Sorted so the same set of formats always hits the same cache entry.
+    emit = sorted(set(emit))
+
+    args = rustc_args(version, optimize) + ["--emit=" + ",".join(emit)]
+    # A linked program is megabytes, where everything else in the cache is
+    # a few kilobytes, so those aren't kept.
+    out, _ = execute(version, "/usr/local/bin/bundle.sh", tuple(args), request.json["code"],
+                     store="link" not in emit)
+    split = out.split(b"\xff", 1)
+    if len(split) != 2:
+        return {"error": split[0].decode()}
+
+    try:
+        artifacts = unpack_artifacts(split[1])
+    except ValueError:
+        return {"error": split[1].decode(errors="replace")}
+
+    # rustc only adapts the output name per format when there's more than
+    # one, otherwise the lone artifact is written to ./out as-is.
+    if len(emit) == 1:
+        names = {"out": BUNDLE_NAMES[emit[0]]}
+    else:
+        names = {"out": "main", "out.s": "main.s", "out.ll": "main.ll"}
+
+    if sum(len(contents) for contents in artifacts.values()) > BUNDLE_SIZE_LIMIT:
+        return {"error": "artifacts are larger than the bundle size limit"}
+
+    buf = io.BytesIO()
+    with zipfile.ZipFile(buf, "w", zipfile.ZIP_DEFLATED) as archive:
+        for name, contents in sorted(artifacts.items()):
+            archive.writestr(names[name], contents)
+
+    response.set_header("Content-Type", "application/zip")
+    response.set_header("Content-Disposition", "attachment; filename=playground.zip")
+    return buf.getvalue()
+
+ASM_FILE = re.compile(r'^\s*\.file\s+(\d+)\s+(?:"[^"]*"\s+)?"([^"]*)"')
+ASM_LOC = re.compile(r"^\s*\.loc\s+(\d+)\s+(\d+)")
+IR_METADATA = re.compile(r"^!(\d+) = (?:distinct )?(.*)$")
+IR_DBG = re.compile(r"!dbg !(\d+)")
+
+def is_input_file(name):
+    # The code is fed to rustc on stdin, so unlike every library source file
+    # its name doesn't end in .rs.
+    return not name.endswith(".rs")
+
+def asm_source_lines(asm):
+    """Maps each instruction line of the asm (numbered from 1) to the source
+    line its preceding .loc directive gives, for locations in the input."""
+    files = {}
+    current = None
+    lines = {}
+    for number, line in enumerate(asm.splitlines(), 1):
+        match = ASM_FILE.match(line)
+        if match:
+            files[match.group(1)] = match.group(2)
+            continue
+        match = ASM_LOC.match(line)
+        if match:
+            file, source_line = match.groups()
+            current = int(source_line) if is_input_file(files.get(file, "")) else None
+            continue
+        stripped = line.strip()
+        if current is not None and stripped and not stripped.startswith((".", "#")) \
+                and not stripped.endswith(":"):
+            lines[number] = current
+    return lines
+
+def ir_source_lines(ir):
+    """Maps each IR line (numbered from 1) with a !dbg location in the input to
+    that location's source line."""
+    metadata = {}
+    for line in ir.splitlines():
+        match = IR_METADATA.match(line)
+        if match:
+            metadata[match.group(1)] = match.group(2)
+
+    def field(node, name):
+        match = re.search(r"\b%s: (?:!(\d+)|\"([^\"]*)\"|(\d+))" % name, metadata.get(node, ""))
+        return None if match is None else next(group for group in match.groups() if group is not None)
+
+    lines = {}
+    for number, line in enumerate(ir.splitlines(), 1):
+        match = IR_DBG.search(line)
+        if match is None or line.startswith("!"):
+            continue
+        location = match.group(1)
+        source_line = field(location, "line")
+        file = field(field(location, "scope"), "file")
+        if source_line is not None and file is not None \
+                and is_input_file(field(file, "filename") or ""):
+            lines[number] = int(source_line)
+    return lines
+
+@route("/crossref.json", method=["POST", "OPTIONS"])
+@enable_post_cors
+@reject_empty_code
+@extractor("version", "stable", ("stable", "beta", "nightly"))
+@extractor("optimize", "2", ("0", "1", "2", "3"))
+def crossref(optimize, version):
+    """Emits asm and LLVM IR for the same build, along with which lines of each
+    came from the same source line. The mapping is only as good as the debug
+    info: it pairs lines that share a source line, not individual instructions,
+    and code that has no location in the input (or whose location LLVM
+    dropped while optimizing) isn't mapped at all. It also relies on the
+    textual debug info format of the toolchain's LLVM, which can change."""
+    args = rustc_args(version, optimize, debug=True) + ["--emit=asm,llvm-ir"]
+    out, _ = execute(version, "/usr/local/bin/bundle.sh", tuple(args), request.json["code"])
+    split = out.split(b"\xff", 1)
+    if len(split) != 2:
+        return {"error": split[0].decode()}
+    try:
+        artifacts = unpack_artifacts(split[1])
+        asm = artifacts["out.s"].decode()
+        ir = artifacts["out.ll"].decode()
+    except (KeyError, ValueError):
+        return {"error": split[1].decode(errors="replace")}
+
+    mapping = collections.defaultdict(lambda: {"asm": [], "llvm-ir": []})
+    for number, source_line in asm_source_lines(asm).items():
+        mapping[source_line]["asm"].append(number)
+    for number, source_line in ir_source_lines(ir).items():
+        mapping[source_line]["llvm-ir"].append(number)
+
+    return {
+        "asm": highlight(asm, get_lexer_by_name(LEXERS["asm"]), HtmlFormatter(nowrap=True)),
+        "llvm-ir": highlight(ir, get_lexer_by_name(LEXERS["llvm-ir"]), HtmlFormatter(nowrap=True)),
+        "mapping": [dict(lines, line=source_line) for source_line, lines in sorted(mapping.items())],
+    }
 
-os.chdir(sys.path[0])
-run(host='0.0.0.0', port=80, server='cherrypy')
+if __name__ == "__main__":
+    os.chdir(sys.path[0])
+    run(host='0.0.0.0', port=80, server='cherrypy')